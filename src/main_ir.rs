@@ -1,10 +1,11 @@
 mod opcode;
 
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 
 use opcode::Opcode;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum IR {
     SHR(u32), // >>>> === SHR(4)
     SHL(u32),
@@ -12,8 +13,10 @@ pub enum IR {
     SUB(u8),
     PUTCHAR,
     GETCHAR,
-    JIZ(u32), // Jump if zero
-    JNZ(u32), // jump if not zero
+    JIZ(u32),                           // Jump if zero
+    JNZ(u32),                           // jump if not zero
+    CLEAR,                              // [-] / [+] === tape[ps] = 0
+    MULADD { offset: i32, factor: u8 }, // tape[ps+offset] += tape[ps] * factor
 }
 
 pub struct Code {
@@ -71,81 +74,459 @@ impl Code {
             }
         }
 
-        Ok(Code { instrs })
+        Ok(Code {
+            instrs: optimize_loops(instrs),
+        })
     }
 }
 
-struct Interpreter {
-    stack: Vec<u8>,
+/// Collapses `[-]`/`[+]`-style clear loops and `[->+<]`-style multiply/copy
+/// loops into single `CLEAR`/`MULADD` instructions, re-targeting the
+/// remaining jumps to match. Loops whose body does anything other than
+/// `ADD`/`SUB`/`SHR`/`SHL`, or that leave the pointer shifted, are left as
+/// ordinary interpreted loops.
+fn optimize_loops(instrs: Vec<IR>) -> Vec<IR> {
+    let mut replacements: Vec<(usize, usize, Vec<IR>)> = Vec::new();
+
+    let mut i = 0;
+    while i < instrs.len() {
+        if let IR::JIZ(target) = instrs[i] {
+            let end = target as usize;
+            let body = &instrs[i + 1..end];
+
+            let is_simple = body
+                .iter()
+                .all(|ir| matches!(ir, IR::ADD(_) | IR::SUB(_) | IR::SHR(_) | IR::SHL(_)));
+
+            if is_simple {
+                let mut shift: i64 = 0;
+                let mut delta: BTreeMap<i32, u8> = BTreeMap::new();
+
+                for ir in body {
+                    match ir {
+                        IR::SHR(x) => shift += *x as i64,
+                        IR::SHL(x) => shift -= *x as i64,
+                        IR::ADD(x) => {
+                            let e = delta.entry(shift as i32).or_insert(0);
+                            *e = e.wrapping_add(*x);
+                        }
+                        IR::SUB(x) => {
+                            let e = delta.entry(shift as i32).or_insert(0);
+                            *e = e.wrapping_sub(*x);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                if shift == 0 {
+                    let own_delta = delta.get(&0).copied().unwrap_or(0);
+                    let others: Vec<(i32, u8)> = delta
+                        .iter()
+                        .filter(|&(&offset, &factor)| offset != 0 && factor != 0)
+                        .map(|(&offset, &factor)| (offset, factor))
+                        .collect();
+
+                    if others.is_empty() && (own_delta == 255 || own_delta == 1) {
+                        replacements.push((i, end, vec![IR::CLEAR]));
+                    } else if own_delta == 255 {
+                        let mut replacement: Vec<IR> = others
+                            .into_iter()
+                            .map(|(offset, factor)| IR::MULADD { offset, factor })
+                            .collect();
+                        replacement.push(IR::CLEAR);
+                        replacements.push((i, end, replacement));
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if replacements.is_empty() {
+        return instrs;
+    }
+
+    let mut out: Vec<IR> = Vec::with_capacity(instrs.len());
+    let mut old_to_new: Vec<u32> = vec![0; instrs.len() + 1];
+    let mut ri = 0;
+    let mut idx = 0;
+
+    while idx < instrs.len() {
+        if ri < replacements.len() && replacements[ri].0 == idx {
+            let (start, end, replacement) = &replacements[ri];
+            old_to_new[*start..=*end].fill(out.len() as u32);
+            out.extend(replacement.iter().cloned());
+            idx = end + 1;
+            ri += 1;
+        } else {
+            old_to_new[idx] = out.len() as u32;
+            out.push(instrs[idx]);
+            idx += 1;
+        }
+    }
+    old_to_new[instrs.len()] = out.len() as u32;
+
+    for ir in out.iter_mut() {
+        match ir {
+            IR::JIZ(x) | IR::JNZ(x) => *x = old_to_new[*x as usize],
+            _ => {}
+        }
+    }
+
+    out
 }
 
-impl Interpreter {
+/// What `GETCHAR` should do to the current cell when the interpreter's
+/// reader has no more bytes to give, mirroring the three conventions real Brainfuck programs
+/// are written against.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EofMode {
+    /// Leave the cell's value untouched.
+    Unchanged,
+    /// Write 0 into the cell.
+    Zero,
+    /// Write 255 (-1 as u8) into the cell.
+    NegOne,
+}
+
+/// A Brainfuck tape that grows in both directions as the pointer moves
+/// past its current bounds, instead of clamping at the origin the way a
+/// plain right-growing `Vec<u8>` would.
+///
+/// Non-negative logical offsets live in `positive` (offset `n` at index
+/// `n`); negative offsets live in `negative` (offset `-1` at index `0`,
+/// `-2` at index `1`, ...).
+struct Tape {
+    positive: Vec<u8>,
+    negative: Vec<u8>,
+}
+
+impl Tape {
     fn new() -> Self {
-        Self { stack: vec![0; 1] }
+        Self {
+            positive: vec![0],
+            negative: Vec::new(),
+        }
+    }
+
+    fn cell(&mut self, offset: i64) -> &mut u8 {
+        if offset >= 0 {
+            let idx = offset as usize;
+            if idx >= self.positive.len() {
+                self.positive.resize(idx + 1, 0);
+            }
+            &mut self.positive[idx]
+        } else {
+            let idx = (-offset - 1) as usize;
+            if idx >= self.negative.len() {
+                self.negative.resize(idx + 1, 0);
+            }
+            &mut self.negative[idx]
+        }
+    }
+
+    /// Snapshots the tape as a single `Vec<u8>`, from the leftmost cell
+    /// ever touched through the rightmost.
+    fn to_vec(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = self.negative.iter().rev().copied().collect();
+        v.extend_from_slice(&self.positive);
+        v
+    }
+}
+
+/// A Brainfuck interpreter over injectable input/output streams, so the
+/// crate can be embedded and tested without touching real stdio.
+pub struct Interpreter<R: Read, W: Write> {
+    tape: Tape,
+    eof_mode: EofMode,
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    pub fn with_io(reader: R, writer: W) -> Self {
+        Self {
+            tape: Tape::new(),
+            eof_mode: EofMode::Unchanged,
+            reader,
+            writer,
+        }
+    }
+
+    pub fn with_eof_mode(mut self, eof_mode: EofMode) -> Self {
+        self.eof_mode = eof_mode;
+        self
     }
-    fn run(&mut self, data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-        let opcode_code = opcode::Code::from(data)?;
+
+    /// Runs `program`, returning the final tape once it halts.
+    pub fn run(&mut self, program: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let opcode_code = opcode::Code::from(program.to_vec())?;
         let code = Code::from(opcode_code.instrs)?;
 
         let code_len = code.instrs.len();
         let mut pc = 0;
-        let mut ps = 0;
+        let mut ps: i64 = 0;
 
         loop {
             if pc >= code_len {
                 break;
             }
             match code.instrs[pc] {
-                IR::SHL(x) => ps = if ps == 0 { 0 } else { ps - x as usize },
-                IR::SHR(x) => {
-                    ps += x as usize;
-                    if ps >= self.stack.len() {
-                        let expand = ps - self.stack.len() + 1;
-                        for _ in 0..expand {
-                            self.stack.push(0);
-                        }
-                    }
-                }
+                IR::SHL(x) => ps -= x as i64,
+                IR::SHR(x) => ps += x as i64,
                 IR::ADD(x) => {
-                    self.stack[ps] = self.stack[ps].overflowing_add(x).0;
+                    let cell = self.tape.cell(ps);
+                    *cell = cell.overflowing_add(x).0;
                 }
                 IR::SUB(x) => {
-                    self.stack[ps] = self.stack[ps].overflowing_sub(x).0;
+                    let cell = self.tape.cell(ps);
+                    *cell = cell.overflowing_sub(x).0;
                 }
                 IR::PUTCHAR => {
-                    std::io::stdout().write_all(&[self.stack[ps]])?;
+                    self.writer.write_all(&[*self.tape.cell(ps)])?;
                 }
                 IR::GETCHAR => {
                     let mut buf: Vec<u8> = vec![0; 1];
-                    std::io::stdin().read_exact(&mut buf)?;
-                    self.stack[ps] = buf[0];
+                    match self.reader.read_exact(&mut buf) {
+                        Ok(()) => *self.tape.cell(ps) = buf[0],
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            match self.eof_mode {
+                                EofMode::Unchanged => {}
+                                EofMode::Zero => *self.tape.cell(ps) = 0,
+                                EofMode::NegOne => *self.tape.cell(ps) = 255,
+                            }
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
                 }
                 IR::JIZ(x) => {
-                    if self.stack[ps] == 0x00 {
+                    if *self.tape.cell(ps) == 0x00 {
                         pc = x as usize;
                     }
                 }
                 IR::JNZ(x) => {
-                    if self.stack[ps] != 0x00 {
+                    if *self.tape.cell(ps) != 0x00 {
                         pc = x as usize;
                     }
                 }
+                IR::CLEAR => {
+                    *self.tape.cell(ps) = 0;
+                }
+                IR::MULADD { offset, factor } => {
+                    let value = *self.tape.cell(ps);
+                    let cell = self.tape.cell(ps + offset as i64);
+                    *cell = cell.wrapping_add(value.wrapping_mul(factor));
+                }
             }
             pc += 1;
         }
-        Ok(())
+        self.writer.flush()?;
+        Ok(self.tape.to_vec())
+    }
+}
+
+impl<R: Read> Interpreter<R, std::io::BufWriter<Vec<u8>>> {
+    /// Runs with output buffered into memory instead of streamed live, so
+    /// callers (tests, embedders) can capture it into a `Vec<u8>` without
+    /// going through real stdio. Output is only flushed once, at the end.
+    pub fn capturing(reader: R) -> Self {
+        Self::with_io(reader, std::io::BufWriter::new(Vec::new()))
+    }
+
+    /// Consumes the interpreter, returning everything the program wrote.
+    pub fn into_output(self) -> Vec<u8> {
+        self.writer.into_inner().unwrap_or_default()
+    }
+}
+
+/// Lowers the optimized IR to a standalone C source file, for users who
+/// want to `gcc` a hot program down to native speed instead of
+/// interpreting it.
+pub fn emit_c(code: &Code) -> String {
+    let mut out = String::new();
+    out.push_str("#include <stdio.h>\n\n");
+    out.push_str("static unsigned char tape[60000];\n\n");
+    out.push_str("int main(void) {\n");
+    out.push_str("    unsigned char *p = tape + 30000;\n\n");
+
+    let mut indent = 1;
+    for ir in &code.instrs {
+        if matches!(ir, IR::JNZ(_)) {
+            indent -= 1;
+        }
+        let pad = "    ".repeat(indent);
+        match ir {
+            IR::SHR(n) => out.push_str(&format!("{pad}p += {n};\n")),
+            IR::SHL(n) => out.push_str(&format!("{pad}p -= {n};\n")),
+            IR::ADD(n) => out.push_str(&format!("{pad}*p += {n};\n")),
+            IR::SUB(n) => out.push_str(&format!("{pad}*p -= {n};\n")),
+            IR::PUTCHAR => out.push_str(&format!("{pad}putchar(*p);\n")),
+            IR::GETCHAR => out.push_str(&format!("{pad}*p = getchar();\n")),
+            IR::JIZ(_) => out.push_str(&format!("{pad}while (*p) {{\n")),
+            IR::JNZ(_) => out.push_str(&format!("{pad}}}\n")),
+            IR::CLEAR => out.push_str(&format!("{pad}*p = 0;\n")),
+            IR::MULADD { offset, factor } => {
+                out.push_str(&format!("{pad}p[{offset}] += *p * {factor};\n"))
+            }
+        }
+        if matches!(ir, IR::JIZ(_)) {
+            indent += 1;
+        }
     }
+
+    out.push_str("\n    return 0;\n}\n");
+    out
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
-    let data = std::fs::read(&args[1])?;
-    // let code = Code::from(data)?;
 
-    let mut interpreter = Interpreter::new();
-    interpreter.run(data)?;
+    let mut emit_target: Option<String> = None;
+    let mut path: Option<String> = None;
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--emit" {
+            i += 1;
+            emit_target = args.get(i).cloned();
+        } else {
+            path = Some(args[i].clone());
+        }
+        i += 1;
+    }
+    let path = path.ok_or("usage: brainfuck [--emit c] <file.bf>")?;
+    let data = std::fs::read(&path)?;
 
-    // println!("{:?}", code.instrs);
+    match emit_target.as_deref() {
+        Some("c") => {
+            let opcode_code = opcode::Code::from(data)?;
+            let code = Code::from(opcode_code.instrs)?;
+            print!("{}", emit_c(&code));
+        }
+        Some(other) => return Err(format!("unsupported --emit target: {other}").into()),
+        None => {
+            let mut interpreter = Interpreter::with_io(std::io::stdin(), std::io::stdout());
+            interpreter.run(&data)?;
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod optimize_loops_tests {
+    use super::*;
+
+    fn compile(src: &str) -> Code {
+        let opcode_code = opcode::Code::from(src.as_bytes().to_vec()).unwrap();
+        Code::from(opcode_code.instrs).unwrap()
+    }
+
+    #[test]
+    fn clear_loop_collapses_to_clear() {
+        assert_eq!(compile("[-]").instrs, vec![IR::CLEAR]);
+        assert_eq!(compile("[+]").instrs, vec![IR::CLEAR]);
+    }
+
+    #[test]
+    fn multi_target_copy_loop_collapses_to_muladds_and_clear() {
+        let code = compile("[->+>+<<]");
+        assert_eq!(
+            code.instrs,
+            vec![
+                IR::MULADD {
+                    offset: 1,
+                    factor: 1
+                },
+                IR::MULADD {
+                    offset: 2,
+                    factor: 1
+                },
+                IR::CLEAR,
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_with_nonzero_net_shift_stays_interpreted() {
+        let code = compile("[->+]");
+        assert_eq!(
+            code.instrs,
+            vec![IR::JIZ(4), IR::SUB(1), IR::SHR(1), IR::ADD(1), IR::JNZ(0)]
+        );
+    }
+
+    #[test]
+    fn loop_with_io_stays_interpreted() {
+        let code = compile("[.-]");
+        assert_eq!(
+            code.instrs,
+            vec![IR::JIZ(3), IR::PUTCHAR, IR::SUB(1), IR::JNZ(0)]
+        );
+    }
+
+    #[test]
+    fn loop_with_nested_bracket_stays_interpreted() {
+        // The inner `[-]` is a simple clear loop and does get collapsed,
+        // but the outer loop contains a bracket and must not be.
+        let code = compile("[[-]-]");
+        assert!(matches!(code.instrs[0], IR::JIZ(_)));
+        assert!(matches!(code.instrs.last().unwrap(), IR::JNZ(_)));
+        assert!(code.instrs.iter().any(|ir| matches!(ir, IR::CLEAR)));
+        assert_eq!(code.instrs.len(), 4);
+    }
+}
+
+#[cfg(test)]
+mod interpreter_tests {
+    use super::*;
+
+    #[test]
+    fn capturing_runs_a_program_without_touching_stdio() {
+        let mut interpreter = Interpreter::capturing(&b""[..]);
+        interpreter.run(b"++++++++++.").unwrap();
+        assert_eq!(interpreter.into_output(), vec![10]);
+    }
+
+    #[test]
+    fn eof_mode_unchanged_leaves_the_cell_as_is() {
+        let mut interpreter =
+            Interpreter::capturing(&b""[..]).with_eof_mode(EofMode::Unchanged);
+        // Set the cell to a known value before the `,` hits EOF on empty input.
+        let program = format!("{},", "+".repeat(66));
+        let tape = interpreter.run(program.as_bytes()).unwrap();
+        assert_eq!(tape[0], 66);
+    }
+
+    #[test]
+    fn eof_mode_zero_writes_zero() {
+        let mut interpreter = Interpreter::capturing(&b""[..]).with_eof_mode(EofMode::Zero);
+        let tape = interpreter.run(b"+,").unwrap();
+        assert_eq!(tape[0], 0);
+    }
+
+    #[test]
+    fn eof_mode_neg_one_writes_255() {
+        let mut interpreter = Interpreter::capturing(&b""[..]).with_eof_mode(EofMode::NegOne);
+        let tape = interpreter.run(b",").unwrap();
+        assert_eq!(tape[0], 255);
+    }
+
+    #[test]
+    fn emit_c_lowers_add_and_putchar() {
+        let opcode_code = opcode::Code::from(b"+.".to_vec()).unwrap();
+        let code = Code::from(opcode_code.instrs).unwrap();
+        let c = emit_c(&code);
+        assert!(c.starts_with("#include <stdio.h>"));
+        assert!(c.contains("*p += 1;\n"));
+        assert!(c.contains("putchar(*p);\n"));
+    }
+
+    #[test]
+    fn tape_grows_in_both_directions() {
+        let mut tape = Tape::new();
+        *tape.cell(-1) = 9;
+        *tape.cell(0) = 1;
+        *tape.cell(2) = 3;
+        assert_eq!(tape.to_vec(), vec![9, 1, 0, 3]);
+    }
+}